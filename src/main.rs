@@ -1,101 +1,288 @@
-extern crate futures;
-extern crate futures_timer;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate num_cpus;
 extern crate simplelog;
 
+mod platform;
+
 use std::env;
 use std::process;
 use std::{thread, time};
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use simplelog::{CombinedLogger, Config, LogLevelFilter, SimpleLogger};
-use std::time::Duration;
-use futures_timer::Delay;
-use futures::prelude::*;
 
-// Sleep interval between temperature checking.
-const SLEEP_TIME_MILLI: u64 = 500;
+use platform::{PlatformBackend, Sensor};
 
-// Interval between frequency increase operation
-const INCR_TIME_MILLI: u64 = 1000;
+// Sample interval between temperature checks and PID control updates.
+const SLEEP_TIME_MILLI: u64 = 500;
 
-// Interval between frequency decrease operation
-const DECR_TIME_MILLI: u64 = 100;
+// File where the aggregate CPU time counters are collected.
+const PROC_STAT_FILE: &'static str = "/proc/stat";
 
-// File where minimum supported frequency should be collected.
-const MIN_FREQ_FILE: &'static str = "/sys/devices/system/cpu/cpu0/cpufreq/cpuinfo_min_freq";
+// Default percent-busy floor: below this, the machine is considered idle and
+// frequency is never decreased purely for being warm.
+const DEFAULT_UTIL_FLOOR: f64 = 20.0;
 
-// File where maximum supported frequency should be collected.
-const MAX_FREQ_FILE: &'static str = "/sys/devices/system/cpu/cpu0/cpufreq/cpuinfo_max_freq";
+// Multiplier applied to dt, when idle, before stepping the PID controller,
+// so a warm-but-idle machine recovers to full frequency faster than the
+// base gains alone would produce. Decreases are already held back by the
+// idle gate below, so this only speeds up the upward direction in practice.
+const IDLE_RECOVERY_DT_SCALE: f64 = 4.0;
 
 // Step size to change cpu frequency. 100Mhz step
 const STEP_FREQ: u64 = 100000;
 
-// Possible files where current temperature should be collected.
-const POSSIBLE_TEMP_FILES: &'static [&'static str] = &[
-	"/sys/class/thermal/thermal_zone1/temp",
-	"/sys/class/thermal/thermal_zone2/temp",
-	"/sys/class/hwmon/hwmon1/temp1_input",
-	"/sys/class/hwmon/hwmon2/temp1_input",
-	"/sys/class/hwmon/hwmon1/device/temp1_input",
-	"/sys/class/hwmon/hwmon2/device/temp1_input",
-];
+// When no target temperature is given on the command line, fall back to the
+// selected sensor's critical temperature minus this many degrees.
+const DEFAULT_CRIT_SAFETY_MARGIN: u64 = 10;
+
+// Default number of samples kept in the sliding temperature window.
+const DEFAULT_WINDOW_CAPACITY: usize = 16;
 
-// For spikes in temperature (a very sudden workload)
-const DEACCR_RATIO: f64 = (1.0 / 4.0);
+// Default degrees above the target temperature at which, once frequency
+// scaling has already bottomed out, cores start getting offlined.
+const DEFAULT_HOTPLUG_MARGIN: u64 = 5;
+
+// Default hysteresis band: a core is brought back online only once the
+// temperature has dropped this many degrees below the hotplug threshold.
+const DEFAULT_HOTPLUG_HYSTERESIS: u64 = 5;
+
+// Default PID gains: frequency (kHz) adjustment per degree of error, per
+// degree-second of accumulated error, and per degree/second of error slew.
+const DEFAULT_KP: f64 = 50_000.0;
+const DEFAULT_KI: f64 = 500.0;
+const DEFAULT_KD: f64 = 10_000.0;
 
 lazy_static! {
-    static ref FREQUENCY: std::sync::Arc<std::sync::Mutex<u64>> =
-        Arc::new(Mutex::new(max_frequency()));
+    // Whether cpuN has been offlined by the hotplug thermal stage, indexed
+    // by core id. Fixed-size at the core count seen at startup so later
+    // offlining can never shrink the range we iterate over.
+    static ref OFFLINED_CORES: std::sync::Arc<std::sync::Mutex<Vec<bool>>> =
+        Arc::new(Mutex::new(vec![false; num_cpus::get()]));
+}
+
+// Set by the SIGINT/SIGTERM handler; the main loop polls it each tick so
+// shutdown always runs on the main thread, never inside a signal handler.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+// Picks the first discovered sensor whose chip name, label, or combined
+// "chip/label" string contains `query` as a substring, e.g.
+// "coretemp/Package id 0" or just "coretemp".
+fn select_sensor<'a>(sensors: &'a [Sensor], query: &str) -> Option<&'a Sensor> {
+    sensors.iter().find(|s| {
+        let label = s.label.as_ref().map(|l| l.as_str()).unwrap_or("");
+        let combined = format!("{}/{}", s.chip, label);
+        combined.contains(query) || s.chip.contains(query) || label.contains(query)
+    })
+}
+
+// Chip-name substrings of known CPU thermal sources, checked in priority
+// order when no --sensor was given: Linux hwmon drivers plus "smc", the
+// chip name MacBackend::discover_sensors() reports for the Apple SMC.
+const KNOWN_CPU_CHIPS: &'static [&'static str] =
+    &["coretemp", "k10temp", "zenpower", "cpu_thermal", "soc_thermal", "smc"];
+
+// Picks a sensible default sensor when none was requested via --sensor.
+// `fs::read_dir` order is OS-defined, not sorted by chip name or hwmon
+// index, so picking sensors[0] directly can silently land on a battery or
+// NVMe sensor instead of the CPU. Prefer a chip known to report CPU
+// temperature first, picking the first sensor discovered for that chip so a
+// backend's own probe order (e.g. macOS's CANDIDATE_TEMP_KEYS priority) is
+// preserved; otherwise fall back to the lexicographically first chip/label
+// pair so the choice is at least deterministic.
+fn default_sensor(sensors: &[Sensor]) -> Sensor {
+    let known = KNOWN_CPU_CHIPS
+        .iter()
+        .find_map(|name| sensors.iter().find(|s| s.chip.to_lowercase().contains(name)));
+    if let Some(s) = known {
+        return s.clone();
+    }
+
+    let mut sorted: Vec<&Sensor> = sensors.iter().collect();
+    sorted.sort_by(|a, b| (&a.chip, &a.label).cmp(&(&b.chip, &b.label)));
+    sorted[0].clone()
 }
 
-fn parse_int_file(path: String) -> u64 {
+// A snapshot of the aggregate "cpu" line of /proc/stat: (idle_time, total_time).
+type CpuSnapshot = (u64, u64);
+
+// Reads the aggregate CPU line of /proc/stat: "cpu  user nice system idle
+// iowait irq softirq steal guest guest_nice", returning (idle + iowait, sum
+// of the first 8 fields). guest/guest_nice are already folded into
+// user/nice by the kernel's own accounting, so they're excluded from the
+// total rather than double-counted.
+fn read_cpu_snapshot() -> Option<CpuSnapshot> {
     let mut content = String::new();
-    let mut fp = File::open(path).unwrap();
-    fp.read_to_string(&mut content).unwrap();
-    content.trim().parse::<u64>().unwrap()
+    File::open(PROC_STAT_FILE).ok()?.read_to_string(&mut content).ok()?;
+    let line = content.lines().next()?;
+    let fields: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|f| f.parse::<u64>().ok())
+        .collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    let idle = fields[3] + fields.get(4).cloned().unwrap_or(0);
+    let total: u64 = fields.iter().take(8).sum();
+    Some((idle, total))
 }
 
-fn min_frequency() -> u64 {
-    parse_int_file(MIN_FREQ_FILE.to_string())
+// Computes percent-busy over the interval between two /proc/stat snapshots.
+fn cpu_utilization(prev: CpuSnapshot, cur: CpuSnapshot) -> f64 {
+    let idle_delta = cur.0.saturating_sub(prev.0);
+    let total_delta = cur.1.saturating_sub(prev.1);
+    if total_delta == 0 {
+        return 0.0;
+    }
+    100.0 * (1.0 - (idle_delta as f64 / total_delta as f64))
 }
 
-fn max_frequency() -> u64 {
-    parse_int_file(MAX_FREQ_FILE.to_string())
+// Fixed-capacity ring buffer of the most recent temperature samples, used to
+// smooth out sub-second blips before they reach the throttle/boost decision.
+struct TempWindow {
+    data: Vec<u64>,
+    idx: usize,
+    filled: usize,
 }
 
-fn get_temp() -> u64 {
-    // Gets the highest sensor temperature
-    let mut max_temp = 0;
-    for file in POSSIBLE_TEMP_FILES {
-        if Path::new(file).exists() {
-            let sensor_temp = parse_int_file(file.to_string()) / 1000;
-            if max_temp < sensor_temp {
-                max_temp = sensor_temp;
-                info!("got temp from: {}", file);
-            }
+impl TempWindow {
+    fn new(capacity: usize) -> TempWindow {
+        TempWindow {
+            data: vec![0; capacity],
+            idx: 0,
+            filled: 0,
         }
     }
-    if max_temp == 0 {
-        error!("impossible to collect current cpu temperature!");
-        process::exit(1);
+
+    fn push(&mut self, sample: u64) {
+        self.data[self.idx] = sample;
+        self.idx = (self.idx + 1) % self.data.len();
+        if self.filled < self.data.len() {
+            self.filled += 1;
+        }
+    }
+
+    fn mean(&self) -> u64 {
+        if self.filled == 0 {
+            return 0;
+        }
+        let sum: u64 = self.data.iter().take(self.filled).sum();
+        sum / self.filled as u64
+    }
+}
+
+// Snaps `freq` to the nearest STEP_FREQ multiple above min_freq, then
+// clamps the result back into [min_freq, max_freq].
+fn snap_to_step(freq: f64, min_freq: u64, max_freq: u64) -> u64 {
+    let steps = ((freq - min_freq as f64) / STEP_FREQ as f64).round();
+    let snapped = min_freq as f64 + steps * STEP_FREQ as f64;
+    (snapped.max(min_freq as f64).min(max_freq as f64)) as u64
+}
+
+// Discrete PID controller whose setpoint is the target temperature and
+// whose process variable is the (windowed) current temperature. Its output
+// is a frequency ceiling, bounded to [min_freq, max_freq] with anti-windup
+// on the integral term: the integral is clamped so its contribution never
+// exceeds the max_freq - min_freq span, which bounds (but does not remove)
+// the bias a long period of being over/under target leaves behind.
+struct PidController {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    output_base: f64,
+    min_freq: u64,
+    max_freq: u64,
+    integral: f64,
+    prev_error: Option<f64>,
+}
+
+impl PidController {
+    fn new(kp: f64, ki: f64, kd: f64, min_freq: u64, max_freq: u64) -> PidController {
+        PidController {
+            kp: kp,
+            ki: ki,
+            kd: kd,
+            // Full speed is the natural operating point when error is zero.
+            output_base: max_freq as f64,
+            min_freq: min_freq,
+            max_freq: max_freq,
+            integral: 0.0,
+            prev_error: None,
+        }
+    }
+
+    // error = target - temp; dt in seconds.
+    fn step(&mut self, error: f64, dt: f64) -> u64 {
+        self.integral += error * dt;
+        if self.ki != 0.0 {
+            let span = (self.max_freq - self.min_freq) as f64;
+            let integral_limit = span / self.ki.abs();
+            self.integral = self.integral.max(-integral_limit).min(integral_limit);
+        }
+
+        let derivative = match self.prev_error {
+            Some(prev) => (error - prev) / dt,
+            None => 0.0,
+        };
+        self.prev_error = Some(error);
+
+        let output =
+            self.output_base + self.kp * error + self.ki * self.integral + self.kd * derivative;
+        let clamped = output.max(self.min_freq as f64).min(self.max_freq as f64);
+        snap_to_step(clamped, self.min_freq, self.max_freq)
+    }
+}
+
+// Applies a frequency cap through the active platform backend, passing the
+// current hotplug state so offlined cores are skipped.
+fn apply_freq(backend: &dyn PlatformBackend, freq: u64) {
+    let offlined = OFFLINED_CORES.lock().unwrap();
+    backend.set_freq(freq, &offlined);
+}
+
+// Offlines the lowest-numbered online core above cpu0 (which is never
+// offlineable), returning its id. Returns None if every core is already
+// offlined.
+fn offline_next_core() -> Option<usize> {
+    let mut offlined = OFFLINED_CORES.lock().unwrap();
+    for c in 1..offlined.len() {
+        if !offlined[c] {
+            offlined[c] = true;
+            platform::set_core_online(c, false);
+            return Some(c);
+        }
     }
-    return max_temp;
+    None
 }
 
-fn set_freq(freq: u64) {
-    info!("setting frequency to {}", freq);
-    for c in 0..num_cpus::get() {
-        let path = format!("/sys/devices/system/cpu/cpu{}/cpufreq/scaling_max_freq", c);
-        let mut fp = File::create(path).unwrap();
-        fp.write_all(format!("{}\n", freq).as_bytes()).unwrap();
+// Re-onlines the highest-numbered offlined core, i.e. undoes the most
+// recent offline_next_core() call first. Returns None if nothing is offlined.
+fn online_next_core() -> Option<usize> {
+    let mut offlined = OFFLINED_CORES.lock().unwrap();
+    for c in (1..offlined.len()).rev() {
+        if offlined[c] {
+            offlined[c] = false;
+            platform::set_core_online(c, true);
+            return Some(c);
+        }
     }
+    None
+}
+
+// Brings every core the daemon has offlined back online.
+fn restore_all_cores() {
+    while online_next_core().is_some() {}
 }
 
 fn main() {
@@ -104,81 +291,445 @@ fn main() {
     ]).unwrap();
 
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        error!("usage: {} <max temp>", args[0]);
+    let mut sensor_query: Option<String> = None;
+    let mut max_temp_arg: Option<String> = None;
+    let mut window_capacity: usize = DEFAULT_WINDOW_CAPACITY;
+    let mut util_floor: f64 = DEFAULT_UTIL_FLOOR;
+    let mut hotplug_margin: u64 = DEFAULT_HOTPLUG_MARGIN;
+    let mut hotplug_hysteresis: u64 = DEFAULT_HOTPLUG_HYSTERESIS;
+    let mut kp: f64 = DEFAULT_KP;
+    let mut ki: f64 = DEFAULT_KI;
+    let mut kd: f64 = DEFAULT_KD;
+    let mut interval_millis: u64 = SLEEP_TIME_MILLI;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--sensor" => {
+                i += 1;
+                if i >= args.len() {
+                    error!("--sensor requires a value, e.g. --sensor coretemp/Package id 0");
+                    process::exit(1);
+                }
+                sensor_query = Some(args[i].clone());
+            }
+            "--window" => {
+                i += 1;
+                if i >= args.len() {
+                    error!("--window requires a sample count");
+                    process::exit(1);
+                }
+                match args[i].parse::<usize>() {
+                    Ok(n) if n > 0 => window_capacity = n,
+                    _ => {
+                        error!("invalid window size: {}", args[i]);
+                        process::exit(1);
+                    }
+                }
+            }
+            "--util-floor" => {
+                i += 1;
+                if i >= args.len() {
+                    error!("--util-floor requires a percentage, e.g. --util-floor 20");
+                    process::exit(1);
+                }
+                match args[i].parse::<f64>() {
+                    Ok(f) if f >= 0.0 && f <= 100.0 => util_floor = f,
+                    _ => {
+                        error!("invalid utilization floor: {}", args[i]);
+                        process::exit(1);
+                    }
+                }
+            }
+            "--hotplug-margin" => {
+                i += 1;
+                if i >= args.len() {
+                    error!("--hotplug-margin requires a degree count");
+                    process::exit(1);
+                }
+                match args[i].parse::<u64>() {
+                    Ok(n) => hotplug_margin = n,
+                    _ => {
+                        error!("invalid hotplug margin: {}", args[i]);
+                        process::exit(1);
+                    }
+                }
+            }
+            "--hotplug-hysteresis" => {
+                i += 1;
+                if i >= args.len() {
+                    error!("--hotplug-hysteresis requires a degree count");
+                    process::exit(1);
+                }
+                match args[i].parse::<u64>() {
+                    Ok(n) => hotplug_hysteresis = n,
+                    _ => {
+                        error!("invalid hotplug hysteresis: {}", args[i]);
+                        process::exit(1);
+                    }
+                }
+            }
+            "--kp" => {
+                i += 1;
+                match args.get(i).and_then(|v| v.parse::<f64>().ok()) {
+                    Some(v) => kp = v,
+                    None => {
+                        error!("--kp requires a numeric gain");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--ki" => {
+                i += 1;
+                match args.get(i).and_then(|v| v.parse::<f64>().ok()) {
+                    Some(v) => ki = v,
+                    None => {
+                        error!("--ki requires a numeric gain");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--kd" => {
+                i += 1;
+                match args.get(i).and_then(|v| v.parse::<f64>().ok()) {
+                    Some(v) => kd = v,
+                    None => {
+                        error!("--kd requires a numeric gain");
+                        process::exit(1);
+                    }
+                }
+            }
+            "--interval" => {
+                i += 1;
+                match args.get(i).and_then(|v| v.parse::<u64>().ok()) {
+                    Some(v) if v > 0 => interval_millis = v,
+                    _ => {
+                        error!("--interval requires a positive millisecond count");
+                        process::exit(1);
+                    }
+                }
+            }
+            other if max_temp_arg.is_none() => max_temp_arg = Some(other.to_string()),
+            other => {
+                error!("unexpected argument: {}", other);
+                process::exit(1);
+            }
+        }
+        i += 1;
+    }
+
+    let backend = platform::ActiveBackend;
+    let sensors = backend.discover_sensors();
+    if sensors.is_empty() {
+        error!("no temperature sensors found on this platform");
         process::exit(1);
     }
+    for sensor in &sensors {
+        info!(
+            "discovered sensor: {}/{} at {}",
+            sensor.chip,
+            sensor.label.clone().unwrap_or_else(|| "-".to_string()),
+            sensor.input_path
+        );
+    }
 
-    let max_temp: u64;
-    match args[1].parse::<u64>() {
-        Err(_) => {
-            error!("invalid temperature: {}", args[1]);
-            process::exit(1);
+    let sensor = match sensor_query {
+        Some(ref query) => match select_sensor(&sensors, query) {
+            Some(s) => s.clone(),
+            None => {
+                error!("no sensor matching '{}' found", query);
+                process::exit(1);
+            }
+        },
+        None => {
+            let chosen = default_sensor(&sensors);
+            if !KNOWN_CPU_CHIPS.iter().any(|name| chosen.chip.to_lowercase().contains(name)) {
+                warn!(
+                    "no known CPU sensor chip found among {} sensor(s); defaulting to \
+                     '{}/{}' (first in sorted order) -- pass --sensor to pick the \
+                     CPU sensor explicitly",
+                    sensors.len(),
+                    chosen.chip,
+                    chosen.label.clone().unwrap_or_else(|| "-".to_string())
+                );
+            }
+            chosen
         }
-        Ok(x) => max_temp = x,
-    }
+    };
+    info!(
+        "using sensor: {}/{}",
+        sensor.chip,
+        sensor.label.clone().unwrap_or_else(|| "-".to_string())
+    );
+
+    let max_temp: u64 = match max_temp_arg {
+        Some(ref s) => match s.parse::<u64>() {
+            Ok(x) => x,
+            Err(_) => {
+                error!("invalid temperature: {}", s);
+                process::exit(1);
+            }
+        },
+        // Prefer crit (minus the safety margin) when the sensor publishes
+        // one; fall back to its max threshold -- already a "stay under
+        // this" limit from the vendor, so used directly -- before giving up
+        // and requiring an explicit target temperature.
+        None => match sensor.crit.map(|c| c.saturating_sub(DEFAULT_CRIT_SAFETY_MARGIN)).or(sensor.max) {
+            Some(t) => t,
+            None => {
+                error!(
+                    "usage: {} [--sensor <chip/label>] <max temp>; sensor has no critical \
+                     or max temperature reading so a target temperature is required",
+                    args[0]
+                );
+                process::exit(1);
+            }
+        },
+    };
     info!("maximum temperature: {}", max_temp);
     info!("cpu count: {}", num_cpus::get());
-    let min_freq: u64 = min_frequency();
-    info!("minimum frequency supported: {}", min_freq);
-    let max_freq: u64 = max_frequency();
-    info!("maximum frequency supported: {}", max_freq);
-    set_freq(*FREQUENCY.lock().unwrap());
+    let (min_freq, max_freq, freq_capping_available) =
+        match (backend.min_frequency(), backend.max_frequency()) {
+            (Some(min), Some(max)) => {
+                info!("minimum frequency supported: {}", min);
+                info!("maximum frequency supported: {}", max);
+                (min, max, true)
+            }
+            _ => {
+                info!(
+                    "frequency capping unavailable on this platform; running as a \
+                     read-and-report thermal monitor with no throttling stage"
+                );
+                (0, 0, false)
+            }
+        };
+    info!("temperature window: {} samples", window_capacity);
+    info!("utilization floor: {}%", util_floor);
+    let hotplug_temp = max_temp + hotplug_margin;
+    info!(
+        "hotplug temperature: {} (hysteresis {})",
+        hotplug_temp, hotplug_hysteresis
+    );
+    info!(
+        "PID gains: Kp={} Ki={} Kd={}, sample interval {}ms",
+        kp, ki, kd, interval_millis
+    );
+    let mut current_freq = max_freq;
+    apply_freq(&backend, current_freq);
+
+    unsafe {
+        // Cast through a pointer rather than straight to an integer so this
+        // doesn't trip the fn-item-to-integer lint.
+        libc::signal(libc::SIGINT, request_shutdown as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_shutdown as *const () as libc::sighandler_t);
+    }
+
+    let mut window = TempWindow::new(window_capacity);
+    let mut prev_cpu_snapshot = read_cpu_snapshot().unwrap_or((0, 0));
+    let mut pid = PidController::new(kp, ki, kd, min_freq, max_freq);
+    let dt = interval_millis as f64 / 1000.0;
 
     loop {
-        let temp = get_temp();
-        if temp > max_temp && *FREQUENCY.lock().unwrap() > min_freq {
-            // decrease frequency
-            let min_freq = min_freq.clone();
-            thread::spawn(move || {
-                let mut lock = FREQUENCY.try_lock();
-                Delay::new(Duration::from_millis(DECR_TIME_MILLI))
-                    .map(|()| {
-                        if let Ok(ref mut cur_freq) = lock {
-                            **cur_freq -= STEP_FREQ;
-                            let temp_diff = temp - max_temp;
-                            if temp_diff > 0 {
-                                let new_freq =
-                                    STEP_FREQ * ((DEACCR_RATIO * temp_diff as f64) as u64);
-                                // need to check if the new frequency number wraps around max integer limit
-                                if (**cur_freq - new_freq) > max_freq {
-                                    **cur_freq = min_freq;
-                                } else {
-                                    **cur_freq -= new_freq;
-                                }
-                            }
-                            if **cur_freq < min_freq {
-                                **cur_freq = min_freq;
-                            }
-                            set_freq(cur_freq.clone());
-                        }
-                    })
-                    .wait()
-                    .unwrap();
-            });
-        } else if temp < (max_temp - 5) && *FREQUENCY.lock().unwrap() < max_freq {
-            // increase frequency
-            let max_freq = max_freq.clone();
-            thread::spawn(move || {
-                let mut lock = FREQUENCY.try_lock();
-                Delay::new(Duration::from_millis(INCR_TIME_MILLI))
-                    .map(|()| {
-                        if let Ok(ref mut cur_freq) = lock {
-                            **cur_freq += STEP_FREQ;
-                            if **cur_freq > max_freq {
-                                **cur_freq = max_freq;
-                            }
-                            set_freq(cur_freq.clone());
-                        }
-                    })
-                    .wait()
-                    .unwrap();
-            });
-            // .join()
-            //     .expect("thread::spawn failed");
-        }
-
-        info!("current temperature: {}", temp);
-        thread::sleep(time::Duration::from_millis(SLEEP_TIME_MILLI));
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            warn!("shutdown requested: restoring maximum frequency and onlining all cores");
+            restore_all_cores();
+            apply_freq(&backend, max_freq);
+            info!("restore complete, exiting");
+            process::exit(0);
+        }
+
+        let raw_temp = match backend.get_temp(&sensor) {
+            Some(t) => t,
+            None => {
+                warn!(
+                    "failed to read temperature from sensor {}/{}; skipping this tick",
+                    sensor.chip,
+                    sensor.label.clone().unwrap_or_else(|| "-".to_string())
+                );
+                thread::sleep(time::Duration::from_millis(interval_millis));
+                continue;
+            }
+        };
+        window.push(raw_temp);
+        let windowed_temp = window.mean();
+
+        let cur_cpu_snapshot = read_cpu_snapshot().unwrap_or(prev_cpu_snapshot);
+        let utilization = cpu_utilization(prev_cpu_snapshot, cur_cpu_snapshot);
+        prev_cpu_snapshot = cur_cpu_snapshot;
+        let idle = utilization < util_floor;
+
+        // An instant sample at or above the sensor's critical temperature
+        // bypasses the window average so a real thermal emergency is never
+        // smoothed away by older, cooler samples.
+        let is_emergency = sensor.crit.map(|crit| raw_temp >= crit).unwrap_or(false);
+        let temp = if is_emergency { raw_temp } else { windowed_temp };
+        if is_emergency {
+            warn!(
+                "instant temperature {} at/above critical, bypassing window average ({})",
+                raw_temp, windowed_temp
+            );
+        }
+
+        let error = max_temp as f64 - temp as f64;
+        let effective_dt = if idle { dt * IDLE_RECOVERY_DT_SCALE } else { dt };
+        // Step the controller every tick, emergency or not, so its integral
+        // and derivative state stays current; an emergency just overrides
+        // the output rather than skipping the update, otherwise the first
+        // step after a multi-tick emergency would compute its derivative
+        // against a stale, several-dt-old prev_error.
+        let stepped_freq = pid.step(error, effective_dt);
+        let pid_freq = if is_emergency { min_freq } else { stepped_freq };
+
+        // Only let the controller throttle an idle-but-warm machine if this
+        // is an emergency; otherwise hold steady and let it cool on its own
+        // rather than punishing idle latency for ambient heat.
+        if pid_freq < current_freq && idle && !is_emergency {
+            // current_freq unchanged
+        } else {
+            current_freq = pid_freq;
+            apply_freq(&backend, current_freq);
+        }
+
+        // Last-resort thermal stage: frequency scaling has bottomed out but
+        // the chip is still too hot, so start shedding logical cores. Uses
+        // its own hysteresis band so cores aren't flapped on/offline.
+        if freq_capping_available {
+            if current_freq <= min_freq && temp >= hotplug_temp {
+                if let Some(c) = offline_next_core() {
+                    warn!("temperature {} at min frequency; offlined cpu{}", temp, c);
+                }
+            } else if temp <= hotplug_temp.saturating_sub(hotplug_hysteresis) {
+                if let Some(c) = online_next_core() {
+                    info!("temperature {} recovered; brought cpu{} back online", temp, c);
+                }
+            }
+        }
+
+        if freq_capping_available {
+            info!(
+                "current temperature: {} (raw: {}), utilization: {:.1}%, frequency: {}",
+                temp, raw_temp, utilization, current_freq
+            );
+        } else {
+            info!(
+                "current temperature: {} (raw: {}), utilization: {:.1}%, frequency: n/a \
+                 (capping unavailable)",
+                temp, raw_temp, utilization
+            );
+        }
+        thread::sleep(time::Duration::from_millis(interval_millis));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(chip: &str, label: Option<&str>) -> Sensor {
+        Sensor {
+            chip: chip.to_string(),
+            label: label.map(|l| l.to_string()),
+            input_path: String::new(),
+            max: None,
+            crit: None,
+        }
+    }
+
+    #[test]
+    fn select_sensor_matches_by_chip_or_label() {
+        let sensors = vec![
+            sensor("coretemp", Some("Package id 0")),
+            sensor("nvme", Some("Composite")),
+        ];
+        assert_eq!(select_sensor(&sensors, "coretemp").unwrap().chip, "coretemp");
+        assert_eq!(select_sensor(&sensors, "Composite").unwrap().chip, "nvme");
+        assert!(select_sensor(&sensors, "zzz").is_none());
+    }
+
+    #[test]
+    fn default_sensor_prefers_known_cpu_chip() {
+        let sensors = vec![sensor("nvme", None), sensor("coretemp", Some("Package id 0"))];
+        assert_eq!(default_sensor(&sensors).chip, "coretemp");
+    }
+
+    #[test]
+    fn default_sensor_falls_back_to_sorted_first_when_unknown() {
+        let sensors = vec![sensor("zzz", None), sensor("aaa", None)];
+        assert_eq!(default_sensor(&sensors).chip, "aaa");
+    }
+
+    #[test]
+    fn default_sensor_preserves_probe_order_among_known_chips() {
+        // "TC0D" is discovered (probed) first here even though it sorts
+        // after "TC0P" alphabetically -- default_sensor must pick the first
+        // match for the known chip rather than re-sorting.
+        let sensors = vec![sensor("smc", Some("TC0D")), sensor("smc", Some("TC0P"))];
+        assert_eq!(default_sensor(&sensors).label.as_deref(), Some("TC0D"));
+    }
+
+    #[test]
+    fn temp_window_means_before_wraparound() {
+        let mut w = TempWindow::new(4);
+        w.push(10);
+        w.push(20);
+        assert_eq!(w.mean(), 15);
+    }
+
+    #[test]
+    fn temp_window_means_after_wraparound() {
+        let mut w = TempWindow::new(3);
+        w.push(10);
+        w.push(20);
+        w.push(30);
+        w.push(60); // wraps around, overwriting the 10
+        assert_eq!(w.mean(), (20 + 30 + 60) / 3);
+    }
+
+    #[test]
+    fn temp_window_empty_mean_is_zero() {
+        let w = TempWindow::new(4);
+        assert_eq!(w.mean(), 0);
+    }
+
+    #[test]
+    fn cpu_utilization_from_synthetic_snapshots() {
+        let prev = (200, 1000);
+        let cur = (400, 2000);
+        assert_eq!(cpu_utilization(prev, cur), 80.0);
+    }
+
+    #[test]
+    fn cpu_utilization_zero_delta_is_zero() {
+        let snap = (100, 500);
+        assert_eq!(cpu_utilization(snap, snap), 0.0);
+    }
+
+    #[test]
+    fn snap_to_step_rounds_to_nearest_step() {
+        assert_eq!(snap_to_step(1_040_000.0, 1_000_000, 2_000_000), 1_000_000);
+        assert_eq!(snap_to_step(1_060_000.0, 1_000_000, 2_000_000), 1_100_000);
+    }
+
+    #[test]
+    fn snap_to_step_clamps_to_bounds() {
+        assert_eq!(snap_to_step(3_000_000.0, 1_000_000, 2_000_000), 2_000_000);
+        assert_eq!(snap_to_step(0.0, 1_000_000, 2_000_000), 1_000_000);
+    }
+
+    #[test]
+    fn pid_controller_clamps_output_to_freq_bounds() {
+        let mut pid = PidController::new(1_000_000.0, 0.0, 0.0, 1_000_000, 2_000_000);
+        assert_eq!(pid.step(100.0, 1.0), 2_000_000);
+        assert_eq!(pid.step(-100.0, 1.0), 1_000_000);
+    }
+
+    #[test]
+    fn pid_controller_integral_anti_windup_limits_bias() {
+        let mut pid = PidController::new(0.0, 1_000.0, 0.0, 1_000_000, 2_000_000);
+        for _ in 0..1000 {
+            pid.step(100.0, 1.0);
+        }
+        let span = (2_000_000 - 1_000_000) as f64;
+        let integral_limit = span / 1_000.0;
+        assert!(pid.integral <= integral_limit + 1e-6);
     }
 }
@@ -0,0 +1,129 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::Path;
+
+use super::{PlatformBackend, Sensor};
+
+// File where minimum supported frequency should be collected.
+const MIN_FREQ_FILE: &'static str = "/sys/devices/system/cpu/cpu0/cpufreq/cpuinfo_min_freq";
+
+// File where maximum supported frequency should be collected.
+const MAX_FREQ_FILE: &'static str = "/sys/devices/system/cpu/cpu0/cpufreq/cpuinfo_max_freq";
+
+// Root of the hwmon sysfs hierarchy, scanned to discover temperature sensors.
+const HWMON_ROOT: &'static str = "/sys/class/hwmon";
+
+pub struct LinuxBackend;
+
+fn parse_int_file(path: &str) -> Option<u64> {
+    let mut content = String::new();
+    File::open(path).ok()?.read_to_string(&mut content).ok()?;
+    content.trim().parse::<u64>().ok()
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+// Reads a sysfs millidegree file and converts it to whole degrees celsius.
+fn read_millidegree_file(path: &Path) -> Option<u64> {
+    read_trimmed(path)
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(|v| v / 1000)
+}
+
+// Builds the human-readable chip name for a hwmon directory, e.g.
+// "coretemp" or "nvme (Samsung SSD 970)" when a device model is present.
+fn chip_name(hwmon_dir: &Path) -> String {
+    let name = read_trimmed(&hwmon_dir.join("name")).unwrap_or_else(|| "unknown".to_string());
+    match read_trimmed(&hwmon_dir.join("device").join("model")) {
+        Some(model) => format!("{} ({})", name, model),
+        None => name,
+    }
+}
+
+impl PlatformBackend for LinuxBackend {
+    // Walks /sys/class/hwmon/hwmon*, enumerating every tempN_input and
+    // pairing it with its tempN_label, tempN_max and tempN_crit siblings
+    // when present.
+    fn discover_sensors(&self) -> Vec<Sensor> {
+        let mut sensors = Vec::new();
+        let entries = match fs::read_dir(HWMON_ROOT) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("unable to read {}: {}", HWMON_ROOT, e);
+                return sensors;
+            }
+        };
+
+        for hwmon_entry in entries.filter_map(|e| e.ok()) {
+            let hwmon_dir = hwmon_entry.path();
+            let is_hwmon_dir = hwmon_dir
+                .file_name()
+                .map(|n| n.to_string_lossy().starts_with("hwmon"))
+                .unwrap_or(false);
+            if !is_hwmon_dir {
+                continue;
+            }
+
+            let chip = chip_name(&hwmon_dir);
+            let chip_entries = match fs::read_dir(&hwmon_dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for file_entry in chip_entries.filter_map(|e| e.ok()) {
+                let file_name = file_entry.file_name().to_string_lossy().into_owned();
+                if !file_name.starts_with("temp") || !file_name.ends_with("_input") {
+                    continue;
+                }
+                let prefix = &file_name[..file_name.len() - "_input".len()];
+
+                sensors.push(Sensor {
+                    chip: chip.clone(),
+                    label: read_trimmed(&hwmon_dir.join(format!("{}_label", prefix))),
+                    input_path: hwmon_dir.join(&file_name).to_string_lossy().into_owned(),
+                    max: read_millidegree_file(&hwmon_dir.join(format!("{}_max", prefix))),
+                    crit: read_millidegree_file(&hwmon_dir.join(format!("{}_crit", prefix))),
+                });
+            }
+        }
+
+        sensors
+    }
+
+    // Returns None if the sensor's input file can't be read or parsed, e.g.
+    // a removable device (NVMe/USB) that discover_sensors() found earlier
+    // has since been unplugged.
+    fn get_temp(&self, sensor: &Sensor) -> Option<u64> {
+        parse_int_file(&sensor.input_path).map(|v| v / 1000)
+    }
+
+    fn min_frequency(&self) -> Option<u64> {
+        parse_int_file(MIN_FREQ_FILE)
+    }
+
+    fn max_frequency(&self) -> Option<u64> {
+        parse_int_file(MAX_FREQ_FILE)
+    }
+
+    fn set_freq(&self, freq: u64, offlined: &[bool]) {
+        info!("setting frequency to {}", freq);
+        for c in 0..offlined.len() {
+            if offlined[c] {
+                continue;
+            }
+            let path = format!("/sys/devices/system/cpu/cpu{}/cpufreq/scaling_max_freq", c);
+            let mut fp = File::create(path).unwrap();
+            fp.write_all(format!("{}\n", freq).as_bytes()).unwrap();
+        }
+    }
+}
+
+// Offlines/re-onlines a logical CPU for the hotplug thermal stage. Not part
+// of PlatformBackend since only Linux supports core hotplugging today.
+pub fn set_core_online(core: usize, online: bool) {
+    let path = format!("/sys/devices/system/cpu/cpu{}/online", core);
+    let mut fp = File::create(path).unwrap();
+    fp.write_all(if online { b"1\n" } else { b"0\n" }).unwrap();
+}
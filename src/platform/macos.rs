@@ -0,0 +1,244 @@
+use std::mem;
+use std::os::raw::{c_char, c_void};
+
+use super::{PlatformBackend, Sensor};
+
+// Real CPU temperature on macOS lives behind the Apple System Management
+// Controller (SMC), reachable through IOKit rather than sysctl -- sysctls
+// like machdep.xcpm.cpu_thermal_level only expose a unitless 0-100 thermal
+// pressure level, not degrees Celsius, so they can't stand in for the
+// sensor.crit/max_temp comparisons the rest of this crate does in Celsius.
+// This talks to the "AppleSMC" IOKit service directly using the wire
+// format documented by longstanding open-source SMC tools (osx-cpu-temp,
+// smcFanControl).
+type IoServiceT = u32;
+type IoConnectT = u32;
+type IoObjectT = u32;
+type KernReturnT = i32;
+
+const KERN_SUCCESS: KernReturnT = 0;
+const IO_OBJECT_NULL: IoObjectT = 0;
+
+// Selector for IOConnectCallStructMethod; SMC exposes a single entry point
+// and multiplexes on the command byte inside the struct payload instead.
+const SMC_HANDLE_YPC_EVENT: u32 = 2;
+
+// Command bytes for that payload.
+const SMC_CMD_READ_KEYINFO: u8 = 9;
+const SMC_CMD_READ_BYTES: u8 = 5;
+
+// CPU temperature keys to probe, in priority order. Coverage here is
+// Intel-only: the 4-character SMC key names Apple Silicon Macs use for
+// CPU die temperature aren't publicly documented, so discover_sensors()
+// simply finds nothing on those machines today.
+const CANDIDATE_TEMP_KEYS: &'static [&'static str] =
+    &["TC0P", "TC0D", "TC0H", "TC0E", "TC0F"];
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SmcVersion {
+    major: u8,
+    minor: u8,
+    build: u8,
+    reserved: u8,
+    release: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SmcPLimitData {
+    version: u16,
+    length: u16,
+    cpu_p_limit: u32,
+    gpu_p_limit: u32,
+    mem_p_limit: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SmcKeyInfo {
+    data_size: u32,
+    data_type: u32,
+    data_attributes: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SmcParam {
+    key: u32,
+    vers: SmcVersion,
+    p_limit_data: SmcPLimitData,
+    key_info: SmcKeyInfo,
+    result: u8,
+    status: u8,
+    data8: u8,
+    data32: u32,
+    bytes: [u8; 32],
+}
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    fn IOServiceMatching(name: *const c_char) -> *mut c_void;
+    fn IOServiceGetMatchingService(master_port: u32, matching: *mut c_void) -> IoServiceT;
+    fn IOServiceOpen(
+        service: IoServiceT,
+        owning_task: u32,
+        ty: u32,
+        connect: *mut IoConnectT,
+    ) -> KernReturnT;
+    fn IOServiceClose(connect: IoConnectT) -> KernReturnT;
+    fn IOObjectRelease(object: IoObjectT) -> KernReturnT;
+    fn IOConnectCallStructMethod(
+        connect: IoConnectT,
+        selector: u32,
+        input: *const c_void,
+        input_size: usize,
+        output: *mut c_void,
+        output_size: *mut usize,
+    ) -> KernReturnT;
+}
+
+// Packs a 4-character SMC key (or data-type tag) into the big-endian u32
+// the wire format uses, e.g. "TC0P" -> 0x5443_3050.
+fn key_code(key: &str) -> u32 {
+    let bytes = key.as_bytes();
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) | (bytes[3] as u32)
+}
+
+// Opens a connection to the AppleSMC IOKit service, or None if this Mac
+// doesn't expose one (e.g. running under conditions where IOKit access is
+// unavailable).
+fn open_smc() -> Option<IoConnectT> {
+    unsafe {
+        let matching = IOServiceMatching(b"AppleSMC\0".as_ptr() as *const c_char);
+        if matching.is_null() {
+            return None;
+        }
+        let service = IOServiceGetMatchingService(0 /* kIOMasterPortDefault */, matching);
+        if service == IO_OBJECT_NULL {
+            return None;
+        }
+
+        let mut connect: IoConnectT = 0;
+        let ret = IOServiceOpen(service, libc::mach_task_self(), 0, &mut connect);
+        IOObjectRelease(service);
+        if ret != KERN_SUCCESS {
+            return None;
+        }
+        Some(connect)
+    }
+}
+
+// Reads one SMC key as a float, interpreting whichever of the two common
+// temperature encodings the key reports itself as.
+fn read_smc_key(conn: IoConnectT, key: &str) -> Option<f32> {
+    unsafe {
+        let mut input: SmcParam = mem::zeroed();
+        input.key = key_code(key);
+        input.data8 = SMC_CMD_READ_KEYINFO;
+
+        let mut output: SmcParam = mem::zeroed();
+        let mut output_size = mem::size_of::<SmcParam>();
+        let ret = IOConnectCallStructMethod(
+            conn,
+            SMC_HANDLE_YPC_EVENT,
+            &input as *const _ as *const c_void,
+            mem::size_of::<SmcParam>(),
+            &mut output as *mut _ as *mut c_void,
+            &mut output_size,
+        );
+        if ret != KERN_SUCCESS || output.result != 0 || output.key_info.data_size == 0 {
+            return None;
+        }
+
+        let data_type = output.key_info.data_type;
+        input.key_info = output.key_info;
+        input.data8 = SMC_CMD_READ_BYTES;
+
+        let mut output: SmcParam = mem::zeroed();
+        let mut output_size = mem::size_of::<SmcParam>();
+        let ret = IOConnectCallStructMethod(
+            conn,
+            SMC_HANDLE_YPC_EVENT,
+            &input as *const _ as *const c_void,
+            mem::size_of::<SmcParam>(),
+            &mut output as *mut _ as *mut c_void,
+            &mut output_size,
+        );
+        if ret != KERN_SUCCESS || output.result != 0 {
+            return None;
+        }
+
+        if data_type == key_code("sp78") {
+            // Signed 8.8 fixed point split across the first two bytes --
+            // the common encoding for Tx (temperature) keys.
+            let raw = ((output.bytes[0] as i16) << 8) | (output.bytes[1] as i16);
+            return Some(raw as f32 / 256.0);
+        }
+        if data_type == key_code("flt ") {
+            let raw = [output.bytes[0], output.bytes[1], output.bytes[2], output.bytes[3]];
+            return Some(f32::from_le_bytes(raw));
+        }
+
+        None
+    }
+}
+
+pub struct MacBackend;
+
+impl PlatformBackend for MacBackend {
+    // Probes the candidate CPU temperature keys and reports only the ones
+    // that actually read back a value on this machine.
+    fn discover_sensors(&self) -> Vec<Sensor> {
+        let conn = match open_smc() {
+            Some(c) => c,
+            None => return Vec::new(),
+        };
+
+        let sensors = CANDIDATE_TEMP_KEYS
+            .iter()
+            .filter(|key| read_smc_key(conn, key).is_some())
+            .map(|key| Sensor {
+                chip: "smc".to_string(),
+                label: Some(key.to_string()),
+                input_path: String::new(),
+                max: None,
+                crit: None,
+            })
+            .collect();
+
+        unsafe {
+            IOServiceClose(conn);
+        }
+        sensors
+    }
+
+    fn get_temp(&self, sensor: &Sensor) -> Option<u64> {
+        let key = sensor.label.as_ref().map(|l| l.as_str()).unwrap_or("TC0P");
+        let conn = open_smc()?;
+        let temp = read_smc_key(conn, key);
+        unsafe {
+            IOServiceClose(conn);
+        }
+        temp.map(|t| t.round().max(0.0) as u64)
+    }
+
+    // The SMC manages fan/power response to heat itself; there is no
+    // user-writable frequency cap equivalent to Linux's scaling_max_freq.
+    fn min_frequency(&self) -> Option<u64> {
+        None
+    }
+
+    fn max_frequency(&self) -> Option<u64> {
+        None
+    }
+
+    // No writable frequency cap exists on this platform; the daemon falls
+    // back to reporting temperature only (no throttling or hotplug stage).
+    fn set_freq(&self, _freq: u64, _offlined: &[bool]) {}
+}
+
+// Core hotplugging is a Linux-only sysfs mechanism with no macOS
+// equivalent, so this is a no-op and the hotplug thermal stage never
+// offlines anything on this platform.
+pub fn set_core_online(_core: usize, _online: bool) {}
@@ -0,0 +1,41 @@
+#[cfg(target_os = "linux")]
+pub mod linux;
+#[cfg(target_os = "macos")]
+pub mod macos;
+
+#[cfg(target_os = "linux")]
+pub use self::linux::LinuxBackend as ActiveBackend;
+#[cfg(target_os = "macos")]
+pub use self::macos::MacBackend as ActiveBackend;
+
+#[cfg(target_os = "linux")]
+pub use self::linux::set_core_online;
+#[cfg(target_os = "macos")]
+pub use self::macos::set_core_online;
+
+// A single temperature sensor exposed by the platform, together with
+// whatever max/crit thresholds it publishes, if any.
+#[derive(Debug, Clone)]
+pub struct Sensor {
+    pub chip: String,
+    pub label: Option<String>,
+    pub input_path: String,
+    pub max: Option<u64>,
+    pub crit: Option<u64>,
+}
+
+// Everything that differs between operating systems: finding sensors,
+// reading their temperature, and capping CPU frequency. A platform that
+// can't cap frequency (e.g. macOS) returns None from min/max_frequency and
+// makes set_freq a no-op, degrading the daemon to a read-and-report
+// thermal monitor rather than failing to build or run at all.
+pub trait PlatformBackend {
+    fn discover_sensors(&self) -> Vec<Sensor>;
+    // None means the sensor couldn't be read this tick (e.g. a removable
+    // device disappeared); callers must treat that as "skip this tick", not
+    // a fatal error.
+    fn get_temp(&self, sensor: &Sensor) -> Option<u64>;
+    fn min_frequency(&self) -> Option<u64>;
+    fn max_frequency(&self) -> Option<u64>;
+    fn set_freq(&self, freq: u64, offlined: &[bool]);
+}